@@ -1,15 +1,73 @@
-//! Нормализация текста и MD5-хэш для ключей кэша эмбеддингов.
-//! Поведение совместимо с Python: ' '.join(text.lower().split()) + hashlib.md5(...).hexdigest()
+//! Нормализация текста и хэш для ключей кэша эмбеддингов.
+//! Поведение по умолчанию совместимо с Python:
+//! ' '.join(text.lower().split()) + hashlib.md5(...).hexdigest()
+//! Для новых кэшей доступен выбор алгоритма дайджеста (`algo="blake2b"` и т.п.).
 
+use std::borrow::Cow;
+
+use blake2::Blake2b512;
+use digest::Digest;
 use faster_hex::hex_string;
+use pyo3::exceptions::PyValueError;
 use pyo3::prelude::*;
+use rayon::prelude::*;
+use sha2::Sha256;
+use sha3::Sha3_256;
+use unicode_normalization::UnicodeNormalization;
+
+/// Набор символов пунктуации, побайтово идентичный Python `string.punctuation`.
+/// Используется вместо Unicode `\p{P}`/`\p{S}` классов, чтобы `fold_punctuation`
+/// в точности воспроизводил `str.maketrans(string.punctuation, ' ')`.
+const ASCII_PUNCTUATION: &str = "!\"#$%&'()*+,-./:;<=>?@[\\]^_`{|}~";
 
-/// Нормализует текст: нижний регистр, схлопывание пробелов в один пробел.
-/// Эквивалент Python: ' '.join(text.lower().split())
-/// Одна аллокация под результат (без Vec промежуточных срезов).
 #[inline]
-fn normalize(text: &str) -> String {
-    let lower = text.to_lowercase();
+fn is_python_punctuation(c: char) -> bool {
+    c.is_ascii() && ASCII_PUNCTUATION.contains(c)
+}
+
+/// Опции нормализации. Значения по умолчанию воспроизводят исходное
+/// MD5-совместимое поведение, чтобы существующие кэши оставались валидными.
+#[derive(Clone, Copy, Debug, Default)]
+struct NormalizeOptions {
+    /// Применить Unicode NFKC перед токенизацией (схлопывает формы совместимости,
+    /// полноширинные/полуширинные варианты и т.п.).
+    nfkc: bool,
+    /// Использовать полный Unicode case folding вместо `str::to_lowercase`.
+    case_fold: bool,
+    /// Заменить пунктуацию на пробелы перед токенизацией.
+    fold_punctuation: bool,
+}
+
+/// Нормализует текст: нижний регистр, схлопывание пробелов в один пробел,
+/// с опциональными NFKC/case-folding/punctuation-folding проходами.
+/// Эквивалент Python: ' '.join(text.lower().split()) при опциях по умолчанию.
+#[inline]
+fn normalize_with(text: &str, opts: NormalizeOptions) -> String {
+    let nfkc_owned;
+    let working: &str = if opts.nfkc {
+        nfkc_owned = text.nfkc().collect::<String>();
+        &nfkc_owned
+    } else {
+        text
+    };
+
+    let folded: Cow<str> = if opts.fold_punctuation && working.chars().any(is_python_punctuation) {
+        Cow::Owned(
+            working
+                .chars()
+                .map(|c| if is_python_punctuation(c) { ' ' } else { c })
+                .collect(),
+        )
+    } else {
+        Cow::Borrowed(working)
+    };
+
+    let lower = if opts.case_fold {
+        caseless::default_case_fold_str(&folded)
+    } else {
+        folded.to_lowercase()
+    };
+
     let mut out = String::with_capacity(lower.len());
     let mut first = true;
     for word in lower.split_whitespace() {
@@ -22,39 +80,281 @@ fn normalize(text: &str) -> String {
     out
 }
 
-/// Возвращает MD5-хэш нормализованного текста в hex (32 символа).
-/// Эквивалент Python: hashlib.md5(normalized.encode()).hexdigest()
+/// Нормализует текст с поведением по умолчанию (MD5-совместимым).
+/// Используется только тестами; продуктовый код вызывает `normalize_with` напрямую.
+#[cfg(test)]
+fn normalize(text: &str) -> String {
+    normalize_with(text, NormalizeOptions::default())
+}
+
+/// Алгоритм дайджеста для ключей кэша. `Md5` — значение по умолчанию и
+/// единственный вариант, совместимый с исторически существующими
+/// Python-кэшами (`hashlib.md5`). Остальные варианты реализованы поверх
+/// RustCrypto `digest::Digest` для более быстрой/стойкой схемы на новых кэшах.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Algorithm {
+    Md5,
+    Blake2b,
+    Sha256,
+    Sha3_256,
+}
+
+impl Algorithm {
+    fn parse(name: &str) -> PyResult<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "md5" => Ok(Algorithm::Md5),
+            "blake2b" => Ok(Algorithm::Blake2b),
+            "sha256" | "sha-256" => Ok(Algorithm::Sha256),
+            "sha3_256" | "sha3-256" => Ok(Algorithm::Sha3_256),
+            other => Err(PyValueError::new_err(format!(
+                "unknown digest algorithm: {other:?} (expected one of: md5, blake2b, sha256, sha3_256)"
+            ))),
+        }
+    }
+}
+
+/// Хэширует байты выбранным алгоритмом и возвращает lowercase hex.
+/// Нормализация общая для всех алгоритмов (см. `normalize_with`), здесь
+/// различается только финализатор дайджеста.
+#[inline]
+fn digest_hex(algo: Algorithm, bytes: &[u8]) -> String {
+    match algo {
+        Algorithm::Md5 => hex_string(md5::compute(bytes).as_ref()),
+        Algorithm::Blake2b => hex_string(&Blake2b512::digest(bytes)),
+        Algorithm::Sha256 => hex_string(&Sha256::digest(bytes)),
+        Algorithm::Sha3_256 => hex_string(&Sha3_256::digest(bytes)),
+    }
+}
+
+/// Обрезает строку до не более чем `max_bytes` байт, отступая назад до ближайшей
+/// границы символа (`str::is_char_boundary`), чтобы никогда не разрезать
+/// многобайтовую кодовую точку и не захэшировать невалидный UTF-8.
+#[inline]
+fn cap_to_char_boundary(s: &str, max_bytes: usize) -> &str {
+    if s.len() <= max_bytes {
+        return s;
+    }
+    let mut end = max_bytes;
+    while end > 0 && !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
+
+/// Возвращает хэш нормализованного текста в hex, алгоритмом по выбору.
+/// Если задан `max_bytes`, хэш считается по UTF-8-безопасному префиксу
+/// нормализованной строки: два входа с общим обрезанным префиксом
+/// намеренно коллидируют — в этом и есть смысл такого ключа кэша.
+/// Эквивалент Python (для `algo="md5"`, `max_bytes=None`):
+/// hashlib.md5(normalized.encode()).hexdigest()
 #[inline]
+fn text_hash_with(
+    text: &str,
+    opts: NormalizeOptions,
+    algo: Algorithm,
+    max_bytes: Option<usize>,
+) -> String {
+    let normalized = normalize_with(text, opts);
+    let capped = match max_bytes {
+        Some(limit) => cap_to_char_boundary(&normalized, limit),
+        None => normalized.as_str(),
+    };
+    digest_hex(algo, capped.as_bytes())
+}
+
+/// Возвращает MD5-хэш нормализованного текста (поведение по умолчанию).
+/// Используется только тестами; продуктовый код вызывает `text_hash_with` напрямую.
+#[cfg(test)]
 fn text_hash(text: &str) -> String {
-    let normalized = normalize(text);
-    let digest = md5::compute(normalized.as_bytes());
-    hex_string(digest.as_ref())
+    text_hash_with(text, NormalizeOptions::default(), Algorithm::Md5, None)
 }
 
-/// Нормализует текст и возвращает его MD5-хэш в hex.
+/// Нормализует текст и возвращает его хэш в hex.
 /// Используется в embedding_optimizer и semantic_cache для ключей кэша.
-#[inline]
+/// `nfkc`/`case_fold`/`fold_punctuation` по умолчанию выключены и `algo`
+/// по умолчанию `"md5"`, чтобы существующие кэши оставались валидными.
+/// `algo`: один из `"md5"`, `"blake2b"`, `"sha256"`, `"sha3_256"`.
+/// `max_bytes`: если задан, хэш считается по UTF-8-безопасному префиксу
+/// нормализованного текста не длиннее `max_bytes` байт.
 #[pyfunction]
-fn normalize_and_hash(text: &str) -> String {
-    text_hash(text)
+#[pyo3(signature = (text, nfkc=false, case_fold=false, fold_punctuation=false, algo="md5", max_bytes=None))]
+fn normalize_and_hash(
+    text: &str,
+    nfkc: bool,
+    case_fold: bool,
+    fold_punctuation: bool,
+    algo: &str,
+    max_bytes: Option<usize>,
+) -> PyResult<String> {
+    let algo = Algorithm::parse(algo)?;
+    Ok(text_hash_with(
+        text,
+        NormalizeOptions {
+            nfkc,
+            case_fold,
+            fold_punctuation,
+        },
+        algo,
+        max_bytes,
+    ))
 }
 
 /// Нормализует текст (без хэша). Для совместимости с Python _normalize_text.
-#[inline]
 #[pyfunction]
-fn normalize_text(text: &str) -> String {
-    normalize(text)
+#[pyo3(signature = (text, nfkc=false, case_fold=false, fold_punctuation=false))]
+fn normalize_text(text: &str, nfkc: bool, case_fold: bool, fold_punctuation: bool) -> String {
+    normalize_with(
+        text,
+        NormalizeOptions {
+            nfkc,
+            case_fold,
+            fold_punctuation,
+        },
+    )
 }
 
-/// Батч: нормализация и MD5 для списка текстов.
+/// Ниже этого размера батча последовательный путь быстрее, чем оплата
+/// накладных расходов на rayon thread-pool.
+const PARALLEL_THRESHOLD: usize = 64;
+
+/// Батч: нормализация и хэширование для списка текстов.
 /// Меньше переходов Python↔Rust при массовой обработке (embedding_optimizer, semantic_cache).
+///
+/// При `parallel=true`, либо при `parallel=None` и `len(texts) >= PARALLEL_THRESHOLD`,
+/// батч обрабатывается через rayon `par_iter` с освобождённым GIL
+/// (`Python::allow_threads`), чтобы не блокировать другие потоки Python.
+/// Порядок результатов всегда совпадает с порядком входных текстов.
 #[pyfunction]
-fn normalize_and_hash_batch(texts: Vec<String>) -> Vec<String> {
-    let mut out = Vec::with_capacity(texts.len());
-    for s in &texts {
-        out.push(text_hash(s.as_str()));
+#[pyo3(signature = (texts, nfkc=false, case_fold=false, fold_punctuation=false, algo="md5", max_bytes=None, parallel=None))]
+fn normalize_and_hash_batch(
+    py: Python<'_>,
+    texts: Vec<String>,
+    nfkc: bool,
+    case_fold: bool,
+    fold_punctuation: bool,
+    algo: &str,
+    max_bytes: Option<usize>,
+    parallel: Option<bool>,
+) -> PyResult<Vec<String>> {
+    let algo = Algorithm::parse(algo)?;
+    let opts = NormalizeOptions {
+        nfkc,
+        case_fold,
+        fold_punctuation,
+    };
+    let use_parallel = parallel.unwrap_or_else(|| texts.len() >= PARALLEL_THRESHOLD);
+
+    if use_parallel {
+        Ok(py.allow_threads(|| {
+            texts
+                .par_iter()
+                .map(|s| text_hash_with(s.as_str(), opts, algo, max_bytes))
+                .collect()
+        }))
+    } else {
+        let mut out = Vec::with_capacity(texts.len());
+        for s in &texts {
+            out.push(text_hash_with(s.as_str(), opts, algo, max_bytes));
+        }
+        Ok(out)
+    }
+}
+
+/// Активный контекст дайджеста для `CacheHasher`, по одному варианту на алгоритм.
+/// Нормализация общая (см. `CacheHasher::update`), здесь различается только
+/// то, во что скармливаются байты токенов.
+enum DigestState {
+    Md5(md5::Context),
+    Blake2b(Blake2b512),
+    Sha256(Sha256),
+    Sha3_256(Sha3_256),
+}
+
+impl DigestState {
+    fn new(algo: Algorithm) -> Self {
+        match algo {
+            Algorithm::Md5 => DigestState::Md5(md5::Context::new()),
+            Algorithm::Blake2b => DigestState::Blake2b(Blake2b512::new()),
+            Algorithm::Sha256 => DigestState::Sha256(Sha256::new()),
+            Algorithm::Sha3_256 => DigestState::Sha3_256(Sha3_256::new()),
+        }
+    }
+
+    fn update(&mut self, bytes: &[u8]) {
+        match self {
+            DigestState::Md5(ctx) => ctx.consume(bytes),
+            DigestState::Blake2b(h) => h.update(bytes),
+            DigestState::Sha256(h) => h.update(bytes),
+            DigestState::Sha3_256(h) => h.update(bytes),
+        }
+    }
+
+    fn finish(self) -> String {
+        match self {
+            DigestState::Md5(ctx) => hex_string(ctx.compute().as_ref()),
+            DigestState::Blake2b(h) => hex_string(&h.finalize()),
+            DigestState::Sha256(h) => hex_string(&h.finalize()),
+            DigestState::Sha3_256(h) => hex_string(&h.finalize()),
+        }
+    }
+}
+
+/// Инкрементальный хэшер для больших документов: скармливает нормализованные
+/// токены в дайджест по мере поступления чанков, не буферизуя нормализованную
+/// строку целиком в памяти.
+///
+/// Сохраняет точную эквивалентность с ' '.join(text.lower().split()):
+/// между последним токеном одного чанка и первым токеном следующего
+/// вставляется ровно один пробел-разделитель, и только если оба чанка
+/// дали хотя бы один токен.
+#[pyclass]
+struct CacheHasher {
+    // `None` once `finish()` has been called: the object is single-use.
+    state: Option<DigestState>,
+    need_leading_space: bool,
+}
+
+#[pymethods]
+impl CacheHasher {
+    /// `algo`: один из `"md5"` (по умолчанию), `"blake2b"`, `"sha256"`, `"sha3_256"`.
+    #[new]
+    #[pyo3(signature = (algo="md5"))]
+    fn new(algo: &str) -> PyResult<Self> {
+        let algo = Algorithm::parse(algo)?;
+        Ok(Self {
+            state: Some(DigestState::new(algo)),
+            need_leading_space: false,
+        })
+    }
+
+    /// Нормализует (lowercase + схлопывание пробелов) очередной фрагмент текста
+    /// и скармливает его токены дайджесту напрямую, без накопления строки.
+    fn update(&mut self, text: &str) -> PyResult<()> {
+        let state = self.state.as_mut().ok_or_else(Self::already_finished_err)?;
+        let lower = text.to_lowercase();
+        for word in lower.split_whitespace() {
+            if self.need_leading_space {
+                state.update(b" ");
+            }
+            state.update(word.as_bytes());
+            self.need_leading_space = true;
+        }
+        Ok(())
+    }
+
+    /// Финализирует дайджест и возвращает hex-строку. Объект одноразовый:
+    /// повторный вызов `update()`/`finish()` после этого возвращает ошибку
+    /// вместо того, чтобы молча начать новый MD5-контекст.
+    fn finish(&mut self) -> PyResult<String> {
+        let state = self.state.take().ok_or_else(Self::already_finished_err)?;
+        Ok(state.finish())
+    }
+}
+
+impl CacheHasher {
+    fn already_finished_err() -> PyErr {
+        PyValueError::new_err("CacheHasher already finished; create a new instance")
     }
-    out
 }
 
 /// Модуль Python: cache_normalizer
@@ -63,6 +363,7 @@ fn cache_normalizer(m: &Bound<'_, PyModule>) -> PyResult<()> {
     m.add_function(wrap_pyfunction!(normalize_and_hash, m)?)?;
     m.add_function(wrap_pyfunction!(normalize_text, m)?)?;
     m.add_function(wrap_pyfunction!(normalize_and_hash_batch, m)?)?;
+    m.add_class::<CacheHasher>()?;
     Ok(())
 }
 
@@ -117,4 +418,180 @@ mod tests {
         assert_eq!(hashes[1], "d41d8cd98f00b204e9800998ecf8427e");
         assert_eq!(hashes[2], text_hash("test"));
     }
+
+    #[test]
+    fn test_batch_parallel_path_matches_serial_order() {
+        let opts = NormalizeOptions::default();
+        let texts: Vec<String> = (0..200).map(|i| format!("document {i}")).collect();
+        let serial: Vec<String> = texts
+            .iter()
+            .map(|s| text_hash_with(s.as_str(), opts, Algorithm::Md5, None))
+            .collect();
+        let parallel: Vec<String> = texts
+            .par_iter()
+            .map(|s| text_hash_with(s.as_str(), opts, Algorithm::Md5, None))
+            .collect();
+        assert_eq!(serial, parallel, "parallel batch must preserve input order");
+    }
+
+    #[test]
+    fn test_normalize_nfkc_collapses_compatibility_forms() {
+        let opts = NormalizeOptions {
+            nfkc: true,
+            case_fold: false,
+            fold_punctuation: false,
+        };
+        // U+FF28 U+FF45 U+FF4C U+FF4C U+FF4F (fullwidth "Hello") -> NFKC -> ASCII "Hello"
+        let fullwidth = "\u{FF28}\u{FF45}\u{FF4C}\u{FF4C}\u{FF4F}";
+        assert_eq!(normalize_with(fullwidth, opts), "hello");
+    }
+
+    #[test]
+    fn test_normalize_fold_punctuation() {
+        let opts = NormalizeOptions {
+            nfkc: false,
+            case_fold: false,
+            fold_punctuation: true,
+        };
+        assert_eq!(normalize_with("hello, world!", opts), "hello world");
+    }
+
+    #[test]
+    fn test_digest_hex_differs_per_algorithm() {
+        let md5 = digest_hex(Algorithm::Md5, b"hello world");
+        let blake2b = digest_hex(Algorithm::Blake2b, b"hello world");
+        let sha256 = digest_hex(Algorithm::Sha256, b"hello world");
+        let sha3_256 = digest_hex(Algorithm::Sha3_256, b"hello world");
+        assert_eq!(md5.len(), 32);
+        assert_eq!(blake2b.len(), 128);
+        assert_eq!(sha256.len(), 64);
+        assert_eq!(sha3_256.len(), 64);
+        assert_ne!(md5, sha256);
+        assert_ne!(sha256, sha3_256);
+    }
+
+    #[test]
+    fn test_algorithm_parse_default_md5() {
+        assert_eq!(Algorithm::parse("md5").unwrap(), Algorithm::Md5);
+        assert_eq!(Algorithm::parse("SHA256").unwrap(), Algorithm::Sha256);
+        assert!(Algorithm::parse("rot13").is_err());
+    }
+
+    #[test]
+    fn test_text_hash_with_md5_matches_text_hash() {
+        let via_default = text_hash("hello world");
+        let via_explicit = text_hash_with(
+            "hello world",
+            NormalizeOptions::default(),
+            Algorithm::Md5,
+            None,
+        );
+        assert_eq!(via_default, via_explicit);
+    }
+
+    #[test]
+    fn test_cap_to_char_boundary_ascii() {
+        assert_eq!(cap_to_char_boundary("hello world", 5), "hello");
+        assert_eq!(cap_to_char_boundary("hi", 100), "hi");
+    }
+
+    #[test]
+    fn test_cap_to_char_boundary_backs_off_multibyte() {
+        // "café" -> 'é' is 2 bytes (U+00E9 encodes as 0xC3 0xA9); a cap that
+        // lands mid-codepoint must back off to the previous char boundary.
+        let s = "café";
+        assert_eq!(s.len(), 5);
+        assert_eq!(cap_to_char_boundary(s, 4), "caf");
+        assert_eq!(cap_to_char_boundary(s, 5), "café");
+    }
+
+    #[test]
+    fn test_text_hash_with_max_bytes_shared_prefix_collides() {
+        let opts = NormalizeOptions::default();
+        let h1 = text_hash_with("hello world", opts, Algorithm::Md5, Some(5));
+        let h2 = text_hash_with("hello there", opts, Algorithm::Md5, Some(5));
+        assert_eq!(h1, h2, "shared capped prefix must hash identically");
+    }
+
+    #[test]
+    fn test_text_hash_with_max_bytes_none_is_unchanged() {
+        let opts = NormalizeOptions::default();
+        assert_eq!(
+            text_hash_with("hello world", opts, Algorithm::Md5, None),
+            text_hash("hello world")
+        );
+    }
+
+    #[test]
+    fn test_cache_hasher_matches_batch_hash_for_whole_text() {
+        let mut hasher = CacheHasher::new("md5").unwrap();
+        hasher.update("  Hello   World  ").unwrap();
+        assert_eq!(hasher.finish().unwrap(), text_hash("  Hello   World  "));
+    }
+
+    #[test]
+    fn test_cache_hasher_matches_across_chunk_boundary() {
+        // "hello world" split across two update() calls must equal one call.
+        let mut chunked = CacheHasher::new("md5").unwrap();
+        chunked.update("hello").unwrap();
+        chunked.update("world").unwrap();
+        let mut whole = CacheHasher::new("md5").unwrap();
+        whole.update("hello world").unwrap();
+        assert_eq!(chunked.finish().unwrap(), whole.finish().unwrap());
+    }
+
+    #[test]
+    fn test_cache_hasher_skips_separator_for_empty_chunk() {
+        // An update() call that contributes no tokens must not insert a spurious space.
+        let mut a = CacheHasher::new("md5").unwrap();
+        a.update("hello").unwrap();
+        a.update("   ").unwrap();
+        a.update("world").unwrap();
+        let mut b = CacheHasher::new("md5").unwrap();
+        b.update("hello world").unwrap();
+        assert_eq!(a.finish().unwrap(), b.finish().unwrap());
+    }
+
+    #[test]
+    fn test_cache_hasher_empty_input() {
+        let mut hasher = CacheHasher::new("md5").unwrap();
+        assert_eq!(
+            hasher.finish().unwrap(),
+            "d41d8cd98f00b204e9800998ecf8427e"
+        );
+    }
+
+    #[test]
+    fn test_cache_hasher_non_md5_algo() {
+        let mut hasher = CacheHasher::new("sha256").unwrap();
+        hasher.update("hello world").unwrap();
+        assert_eq!(
+            hasher.finish().unwrap(),
+            text_hash_with(
+                "hello world",
+                NormalizeOptions::default(),
+                Algorithm::Sha256,
+                None
+            )
+        );
+    }
+
+    #[test]
+    fn test_cache_hasher_rejects_reuse_after_finish() {
+        let mut hasher = CacheHasher::new("sha256").unwrap();
+        hasher.update("hello world").unwrap();
+        hasher.finish().unwrap();
+        assert!(hasher.update("more").is_err());
+        assert!(hasher.finish().is_err());
+    }
+
+    #[test]
+    fn test_normalize_default_unchanged() {
+        // Default options must reproduce the original MD5-compatible normalization.
+        // Python: ' '.join("  Hello   World  ".lower().split()) == "hello world"
+        assert_eq!(
+            normalize_with("  Hello   World  ", NormalizeOptions::default()),
+            "hello world"
+        );
+    }
 }